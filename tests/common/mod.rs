@@ -0,0 +1,21 @@
+/// A tiny LCG, good enough to drive deterministic pseudo-random test data
+/// without pulling in a `rand` dependency.
+pub struct SimpleRng {
+    seed: u64,
+}
+
+impl SimpleRng {
+    const A: u64 = 6364136223846793005;
+    const C: u64 = 1;
+
+    pub fn new(seed: u64) -> Self { SimpleRng { seed } }
+
+    pub fn next(&mut self) -> u64 {
+        self.seed = self.seed.wrapping_mul(Self::A).wrapping_add(Self::C);
+        self.seed
+    }
+
+    pub fn gen_range(&mut self, min: u64, max: u64) -> u64 {
+        min + (self.next() % (max - min))
+    }
+}
@@ -0,0 +1,475 @@
+use std::fmt::Debug;
+use batcher::batcher::{Batched, BatchedOp, WrappedOp};
+use batcher::utils;
+
+const SHIFT: u32 = 4;
+const SIZE: usize = 16;
+const MASK: u64 = 0xF;
+const MAX_DEPTH: u32 = u64::BITS / SHIFT;
+
+/// A 16-way radix trie keyed on `u64`, branching on one 4-bit nibble per
+/// level (most-significant nibble first) down to a maximum depth of
+/// `u64::BITS / 4`. Unlike `RBTreeMap`, insertion never rebalances: a key's
+/// position is fixed by its bits, so merging two tries is just merging their
+/// child arrays slot by slot.
+#[derive(Clone)]
+pub struct RadixTrieMap<V> {
+    root: Option<Box<Node<V>>>,
+}
+
+#[derive(Clone)]
+enum Node<V> {
+    Leaf(V),
+    Branch([Option<Box<Node<V>>>; SIZE]),
+}
+
+impl<V> Default for RadixTrieMap<V> {
+    fn default() -> Self { Self::new() }
+}
+
+impl<V> RadixTrieMap<V> {
+    pub fn new() -> Self {
+        Self { root: None }
+    }
+
+    pub fn insert(&mut self, key: u64, value: V) {
+        self.root = Some(Self::insert_into(self.root.take(), key, MAX_DEPTH, value));
+    }
+
+    fn insert_into(node: Option<Box<Node<V>>>, key: u64, depth: u32, value: V) -> Box<Node<V>> {
+        if depth == 0 {
+            return Box::new(Node::Leaf(value));
+        }
+        let index = Self::index_at(key, depth);
+        let mut children = match node {
+            Some(node) => match *node {
+                Node::Branch(children) => children,
+                Node::Leaf(_) => unreachable!("radix depth mismatch"),
+            },
+            None => Default::default(),
+        };
+        children[index] = Some(Self::insert_into(children[index].take(), key, depth - 1, value));
+        Box::new(Node::Branch(children))
+    }
+
+    pub fn get(&self, key: u64) -> Option<&V> {
+        Self::get_in(&self.root, key, MAX_DEPTH)
+    }
+
+    fn get_in(node: &Option<Box<Node<V>>>, key: u64, depth: u32) -> Option<&V> {
+        match node.as_ref()?.as_ref() {
+            Node::Leaf(value) => Some(value),
+            Node::Branch(children) => Self::get_in(&children[Self::index_at(key, depth)], key, depth - 1),
+        }
+    }
+
+    pub fn remove(&mut self, key: u64) -> Option<V> {
+        let (root, value) = Self::remove_from(self.root.take(), key, MAX_DEPTH);
+        self.root = root;
+        value
+    }
+
+    fn remove_from(node: Option<Box<Node<V>>>, key: u64, depth: u32) -> (Option<Box<Node<V>>>, Option<V>) {
+        match node.map(|node| *node) {
+            None => (None, None),
+            Some(Node::Leaf(value)) => (None, Some(value)),
+            Some(Node::Branch(mut children)) => {
+                let index = Self::index_at(key, depth);
+                let (updated, value) = Self::remove_from(children[index].take(), key, depth - 1);
+                children[index] = updated;
+                if children.iter().all(Option::is_none) {
+                    (None, value)
+                } else {
+                    (Some(Box::new(Node::Branch(children))), value)
+                }
+            }
+        }
+    }
+
+    fn index_at(key: u64, depth: u32) -> usize {
+        let shift = (depth - 1) * SHIFT;
+        ((key >> shift) & MASK) as usize
+    }
+
+    /// Merges `left` and `right`, combining the value at a key present in
+    /// both via `resolve`. Because a nibble's index already partitions the
+    /// key space, this just zips the two child arrays slot by slot instead
+    /// of the split/join dance `RBTreeMapBy::union` needs to keep itself
+    /// balanced.
+    fn union(left: Option<Box<Node<V>>>, right: Option<Box<Node<V>>>, resolve: &impl Fn(V, V) -> V) -> Option<Box<Node<V>>> {
+        match (left, right) {
+            (None, right) => right,
+            (left, None) => left,
+            (Some(left), Some(right)) => match (*left, *right) {
+                (Node::Leaf(l), Node::Leaf(r)) => Some(Box::new(Node::Leaf(resolve(l, r)))),
+                (Node::Branch(mut children), Node::Branch(other)) => {
+                    for (slot, other_child) in children.iter_mut().zip(other) {
+                        *slot = Self::union(slot.take(), other_child, resolve);
+                    }
+                    Some(Box::new(Node::Branch(children)))
+                }
+                _ => unreachable!("radix depth mismatch between merged tries"),
+            },
+        }
+    }
+}
+
+impl<V: Send + 'static> BatchedOp for RadixTrieMapOp<V> {
+    type Res = Option<V>;
+}
+
+#[derive(Debug)]
+pub enum RadixTrieMapOp<V> {
+    Insert(u64, V),
+    Get(u64),
+    Remove(u64),
+}
+
+/// A single batch-local transformation of a key's value, tagged with its
+/// position in the batch. `Insert`/`Remove` become the constant transforms
+/// `|_| Some(v)`/`|_| None`. Composing two slots for the same key applies
+/// the earlier one's transform first and feeds its result into the later
+/// one's, so each write's callback reports the value its in-batch
+/// predecessor actually produced, or the pre-batch value if it has none.
+/// Mirrors `RBTreeMap`'s `Slot`.
+type Slot<V> = Box<dyn FnOnce(Option<V>) -> Option<V> + Send>;
+
+/// A trie node carrying batch-tagged, not-yet-applied transforms, as built
+/// by [`RadixTrieMap::run_batch`]'s merge and consumed by `apply_merge`.
+type MergeNode<V> = Node<(usize, Slot<V>)>;
+
+impl<V> Batched for RadixTrieMap<V>
+where
+    V: Send + Debug + Clone + 'static,
+{
+    type Op = RadixTrieMapOp<V>;
+
+    fn init() -> Self { Self::new() }
+
+    async fn run_batch(&mut self, ops: Vec<WrappedOp<Self::Op>>) {
+        fn reduce<V>(l: RadixTrieMap<(usize, Slot<V>)>, r: RadixTrieMap<(usize, Slot<V>)>) -> RadixTrieMap<(usize, Slot<V>)>
+        where
+            V: Send + Debug + 'static,
+        {
+            RadixTrieMap {
+                root: RadixTrieMap::union(l.root, r.root, &|a: (usize, Slot<V>), b: (usize, Slot<V>)| {
+                    let (earlier, later) = if a.0 <= b.0 { (a, b) } else { (b, a) };
+                    let index = later.0;
+                    (index, Box::new(move |old| (later.1)((earlier.1)(old))) as Slot<V>)
+                }),
+            }
+        }
+
+        let map = |op: WrappedOp<RadixTrieMapOp<(usize, Slot<V>)>>| -> RadixTrieMap<(usize, Slot<V>)> {
+            match op.0 {
+                RadixTrieMapOp::Insert(key, tagged) => {
+                    let mut singleton = RadixTrieMap::new();
+                    singleton.insert(key, tagged);
+                    singleton
+                }
+                _ => unreachable!("a batch's merge ops are always tagged inserts"),
+            }
+        };
+
+        // Phase one: split the batch into mutations (to be merged into one
+        // trie and folded in below) and reads (buffered until the merge has
+        // landed, so a query observes its own batch's writes).
+        type ReadBuffer<V> = Vec<(u64, Box<dyn FnOnce(Option<V>) + Send>)>;
+        type MutationBuffer<V> = Vec<WrappedOp<RadixTrieMapOp<(usize, Slot<V>)>>>;
+
+        let mut reads: ReadBuffer<V> = Vec::new();
+        let mutations: MutationBuffer<V> = ops
+            .into_iter()
+            .enumerate()
+            .filter_map(|(index, op)| match op.0 {
+                RadixTrieMapOp::Get(key) => {
+                    reads.push((key, op.1));
+                    None
+                }
+                RadixTrieMapOp::Insert(key, value) => {
+                    // Deferred past the merge: the "old" value reported is
+                    // the one left by the same-key write that precedes this
+                    // one in the batch, or the pre-batch value if none does.
+                    let callback = op.1;
+                    let transform: Slot<V> = Box::new(move |old| {
+                        callback(old);
+                        Some(value)
+                    });
+                    Some(WrappedOp(RadixTrieMapOp::Insert(key, (index, transform)), Box::new(|_| {})))
+                }
+                RadixTrieMapOp::Remove(key) => {
+                    let callback = op.1;
+                    let transform: Slot<V> = Box::new(move |old| {
+                        callback(old);
+                        None
+                    });
+                    Some(WrappedOp(RadixTrieMapOp::Insert(key, (index, transform)), Box::new(|_| {})))
+                }
+            })
+            .collect();
+
+        if !mutations.is_empty() {
+            let merged = utils::parallel_reduce(mutations, reduce, map).await;
+            self.apply_merge(merged.root, MAX_DEPTH, 0);
+        }
+
+        for (key, callback) in reads {
+            callback(self.get(key).cloned());
+        }
+    }
+}
+
+impl<V: Clone> RadixTrieMap<V> {
+    /// Folds a merged, tagged mutation trie into `self`. Unlike
+    /// `RBTreeMap::apply_merge`, a trie node doesn't store its own key, so
+    /// this rebuilds it nibble by nibble while descending.
+    fn apply_merge(&mut self, node: Option<Box<MergeNode<V>>>, depth: u32, key: u64) {
+        match node.map(|node| *node) {
+            None => {}
+            Some(Node::Leaf((_, transform))) => {
+                let old = self.get(key).cloned();
+                match transform(old) {
+                    Some(value) => { self.insert(key, value); }
+                    None => { self.remove(key); }
+                }
+            }
+            Some(Node::Branch(children)) => {
+                let shift = (depth - 1) * SHIFT;
+                for (index, child) in children.into_iter().enumerate() {
+                    self.apply_merge(child, depth - 1, key | ((index as u64) << shift));
+                }
+            }
+        }
+    }
+}
+
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::SimpleRng;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_insert_and_get() {
+        let mut map = RadixTrieMap::new();
+        map.insert(4, "value4");
+        map.insert(5, "value5");
+        map.insert(3, "value3");
+        map.insert(1, "value1");
+        map.insert(2, "value2");
+        assert_eq!(map.get(1), Some(&"value1"));
+        assert_eq!(map.get(2), Some(&"value2"));
+        assert_eq!(map.get(4), Some(&"value4"));
+        assert_eq!(map.get(3), Some(&"value3"));
+        assert_eq!(map.get(5), Some(&"value5"));
+        assert_eq!(map.get(6), None);
+    }
+
+    #[test]
+    fn test_insert_overwrite() {
+        let mut map = RadixTrieMap::new();
+        map.insert(10, "value10");
+        assert_eq!(map.get(10), Some(&"value10"));
+
+        // Insert with the same key but different value
+        map.insert(10, "new_value10");
+        assert_eq!(map.get(10), Some(&"new_value10"));
+    }
+
+    #[test]
+    fn test_remove() {
+        let mut map = RadixTrieMap::new();
+        map.insert(10, "value10");
+        map.insert(20, "value20");
+        map.insert(5, "value5");
+
+        assert_eq!(map.remove(10), Some("value10"));
+        assert_eq!(map.get(10), None);
+
+        assert_eq!(map.remove(5), Some("value5"));
+        assert_eq!(map.get(5), None);
+
+        assert_eq!(map.remove(20), Some("value20"));
+        assert_eq!(map.get(20), None);
+
+        // Try to remove a non-existent key
+        assert_eq!(map.remove(30), None);
+    }
+
+    #[test]
+    fn test_remove_from_empty() {
+        let mut map: RadixTrieMap<&str> = RadixTrieMap::new();
+        assert_eq!(map.remove(10), None);
+    }
+
+    #[test]
+    fn test_get_empty() {
+        let map: RadixTrieMap<&str> = RadixTrieMap::new();
+        assert_eq!(map.get(10), None);
+    }
+
+    #[test]
+    fn test_sparse_high_bits() {
+        // Keys that differ only in their most-significant nibbles should
+        // still coexist without clobbering each other.
+        let mut map = RadixTrieMap::new();
+        map.insert(0, "zero");
+        map.insert(1 << 60, "one_high");
+        map.insert(u64::MAX, "max");
+        assert_eq!(map.get(0), Some(&"zero"));
+        assert_eq!(map.get(1 << 60), Some(&"one_high"));
+        assert_eq!(map.get(u64::MAX), Some(&"max"));
+    }
+
+    #[test]
+    fn test_insert_and_remove_sequential() {
+        let mut map = RadixTrieMap::new();
+
+        for i in 0..100u64 {
+            map.insert(i, i * 2);
+            assert_eq!(map.get(i), Some(&(i * 2)));
+        }
+
+        for i in 0..100u64 {
+            assert_eq!(map.remove(i), Some(i * 2));
+            assert_eq!(map.get(i), None);
+        }
+    }
+
+    #[test]
+    fn test_random_inserts_and_gets() {
+        let mut map = RadixTrieMap::new();
+        let mut rng = SimpleRng::new(114514);
+        let mut keys = Vec::new();
+
+        // Insert 1000 pseudo-random key-value pairs
+        for _ in 0 .. 1000 {
+            let key = rng.gen_range(0, 50000);
+            let value = rng.gen_range(0, 500000);
+            if keys.iter().any(|(k, _)| k == &key) {
+                continue;
+            }
+            map.insert(key, value);
+            keys.push((key, value));
+        }
+
+        // Check if all inserted keys return the correct values
+        for (key, value) in keys {
+            assert_eq!(map.get(key), Some(&value));
+        }
+    }
+
+    #[test]
+    fn test_random_inserts_and_removals() {
+        let mut map = RadixTrieMap::new();
+        let mut rng = SimpleRng::new(1919);
+        let mut keys = Vec::new();
+
+        // Insert 1000 pseudo-random key-value pairs
+        for _ in 0 .. 1000 {
+            let key = rng.gen_range(0, 50000);
+            let value = rng.gen_range(0, 500000);
+            if keys.iter().any(|(k, _)| k == &key) {
+                continue;
+            }
+            map.insert(key, value);
+            keys.push((key, value));
+        }
+
+        // Randomly remove keys
+        for (key, value) in keys {
+            assert_eq!(map.get(key), Some(&value));
+            assert_eq!(map.remove(key), Some(value));
+            assert_eq!(map.get(key), None);  // Ensure the key is removed
+        }
+
+        // Ensure the map is empty after all removals
+        assert_eq!(map.get(rng.gen_range(0, 50000)), None);
+    }
+
+    #[test]
+    fn test_random_inserts_gets_and_removals_stress() {
+        let mut map = RadixTrieMap::new();
+        let mut rng = SimpleRng::new(810);
+        let mut keys = Vec::new();
+
+        // Insert 10,000 pseudo-random key-value pairs
+        for _ in 0 .. 10_000 {
+            let key = rng.gen_range(0, 50000);
+            let value = rng.gen_range(0, 500000);
+            if keys.iter().any(|(k, _)| k == &key) {
+                continue;
+            }
+            map.insert(key, value);
+            keys.push((key, value));
+        }
+
+        // Randomly get and remove 5,000 entries
+        for _ in (0 .. 5000).rev() {
+            let index = rng.gen_range(0, keys.len() as u64) as usize;
+            let (key, value) = keys.remove(index);
+            assert_eq!(map.get(key), Some(&value));
+            assert_eq!(map.remove(key), Some(value));
+            assert_eq!(map.get(key), None);  // Ensure the key is removed
+        }
+
+        // Check remaining keys
+        for (key, value) in keys {
+            assert_eq!(map.get(key), Some(&value));
+        }
+    }
+
+    fn run(map: &mut RadixTrieMap<u64>, ops: Vec<RadixTrieMapOp<u64>>) -> Vec<Option<u64>> {
+        let results = Arc::new(Mutex::new(Vec::new()));
+        let wrapped = ops.into_iter().map(|op| {
+            let results = results.clone();
+            WrappedOp(op, Box::new(move |res| results.lock().unwrap().push(res)) as Box<dyn FnOnce(Option<u64>) + Send>)
+        }).collect();
+        futures::executor::block_on(map.run_batch(wrapped));
+        Arc::try_unwrap(results).unwrap().into_inner().unwrap()
+    }
+
+    #[test]
+    fn test_run_batch_matches_sequential_reference() {
+        let mut reference = RadixTrieMap::new();
+        let mut batched = RadixTrieMap::new();
+        let mut rng = SimpleRng::new(4040);
+        let mut ops = Vec::new();
+        for _ in 0 .. 2000 {
+            let key = rng.gen_range(0, 500);
+            let value = rng.gen_range(0, 1_000_000);
+            if rng.gen_range(0, 5) == 0 {
+                reference.remove(key);
+                ops.push(RadixTrieMapOp::Remove(key));
+            } else {
+                reference.insert(key, value);
+                ops.push(RadixTrieMapOp::Insert(key, value));
+            }
+        }
+
+        run(&mut batched, ops);
+
+        for key in 0 .. 500 {
+            assert_eq!(batched.get(key), reference.get(key));
+        }
+    }
+
+    #[test]
+    fn test_run_batch_insert_sees_in_batch_predecessor() {
+        let mut map = RadixTrieMap::new();
+        map.insert(7, 1);
+
+        let results = run(&mut map, vec![
+            RadixTrieMapOp::Insert(7, 111),
+            RadixTrieMapOp::Insert(7, 222),
+            RadixTrieMapOp::Get(7),
+        ]);
+
+        assert_eq!(results[0], Some(1));
+        assert_eq!(results[1], Some(111));
+        assert_eq!(results[2], Some(222));
+        assert_eq!(map.get(7), Some(&222));
+    }
+}
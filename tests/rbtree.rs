@@ -1,111 +1,327 @@
-#![feature(box_patterns)]
-
+use std::cmp::Ordering;
 use std::fmt::Debug;
-use std::future::Future;
+use std::num::NonZeroU32;
+use std::ops::{Bound, RangeBounds};
 use batcher::batcher::{Batched, BatchedOp, WrappedOp};
 use batcher::utils;
 
-#[derive(Clone)]
-pub struct RBTreeMap<K: PartialOrd, V> {
-    root: Option<Box<Node<K, V>>>,
+/// A comparator supplying the ordering [`RBTreeMapBy`] searches and splices
+/// keys by, in place of the key type's own `PartialOrd`/`Ord`.
+pub trait Comparator<K> {
+    fn cmp(&self, a: &K, b: &K) -> Ordering;
+}
+
+/// The default comparator: defers to the key type's own `Ord` impl. This is
+/// what [`RBTreeMap`] plugs in so it behaves exactly like before.
+#[derive(Copy, Clone, Default)]
+pub struct OrdComparator;
+
+impl<K: Ord> Comparator<K> for OrdComparator {
+    fn cmp(&self, a: &K, b: &K) -> Ordering {
+        a.cmp(b)
+    }
+}
+
+/// A compact handle into a [`NodePool`], standing in for what used to be a
+/// `Box<Node<K, V>>` pointer. Stored as `index + 1` so that `Option<NodeHandle>`
+/// packs into the same four bytes as a bare `u32` (the stored value `0` is the
+/// one niche value reserved for `None`), and so a handle is plain `Copy` data instead of
+/// an owned, move-only pointer.
+#[derive(Copy, Clone, PartialEq, Eq)]
+struct NodeHandle(NonZeroU32);
+
+impl NodeHandle {
+    fn new(index: usize) -> Self {
+        NodeHandle(NonZeroU32::new(index as u32 + 1).expect("node pool exceeded u32::MAX entries"))
+    }
+
+    fn index(self) -> usize {
+        (self.0.get() - 1) as usize
+    }
+
+    /// Rebases a handle minted against one pool so it reads correctly
+    /// against a second pool that had `offset` entries before the first
+    /// pool's were appended onto it. See [`NodePool::absorb`].
+    fn shifted(self, offset: u32) -> Self {
+        NodeHandle::new(self.index() + offset as usize)
+    }
 }
 
 #[derive(Copy, Clone)]
 enum Color { Red, Black }
 
 #[derive(Clone)]
-struct Node<K: PartialOrd, V> {
+struct PoolNode<K, V> {
     key: K,
     value: V,
     color: Color,
-    left: Option<Box<Node<K, V>>>,
-    right: Option<Box<Node<K, V>>>,
+    left: Option<NodeHandle>,
+    right: Option<NodeHandle>,
 }
 
-impl<K: PartialOrd, V> Default for RBTreeMap<K, V> {
-    fn default() -> Self { Self::new() }
+/// Backing storage for every [`PoolNode`] an [`RBTreeMapBy`] has allocated,
+/// indexed by [`NodeHandle`]. A modified path is rebuilt the same way the old
+/// `Box`-per-node layout did it -- by constructing replacement nodes bottom-up
+/// and discarding the old ones -- except "discarding" frees a slot back onto
+/// `free` for the next `alloc` to reuse instead of triggering a `dealloc`, and
+/// "constructing" is a `Vec::push` (or a freelist pop) rather than a fresh heap
+/// allocation. Each tree owns its own pool: the per-op singleton trees that
+/// `run_batch`'s `map` step builds each start with a separate one, and `reduce`
+/// appends one onto the other (see [`NodePool::absorb`]) as it merges them, so
+/// a batch's merged tree ends up in one contiguous allocation that is dropped
+/// as a whole when that pool goes out of scope. Subtrees are never shared
+/// between trees or pools.
+#[derive(Clone)]
+struct NodePool<K, V> {
+    slots: Vec<Option<PoolNode<K, V>>>,
+    free: Vec<u32>,
 }
 
-impl<K: PartialOrd, V> RBTreeMap<K, V> {
-    pub fn new() -> Self {
-        Self { root: None }
+impl<K, V> NodePool<K, V> {
+    fn new() -> Self { NodePool { slots: Vec::new(), free: Vec::new() } }
+
+    fn alloc(&mut self, node: PoolNode<K, V>) -> NodeHandle {
+        match self.free.pop() {
+            Some(index) => {
+                self.slots[index as usize] = Some(node);
+                NodeHandle::new(index as usize)
+            }
+            None => {
+                self.slots.push(Some(node));
+                NodeHandle::new(self.slots.len() - 1)
+            }
+        }
+    }
+
+    fn get(&self, handle: NodeHandle) -> &PoolNode<K, V> {
+        self.slots[handle.index()].as_ref().expect("dangling node handle")
+    }
+
+    fn get_mut(&mut self, handle: NodeHandle) -> &mut PoolNode<K, V> {
+        self.slots[handle.index()].as_mut().expect("dangling node handle")
+    }
+
+    /// Removes and returns the node at `handle`, freeing its slot for the
+    /// next `alloc`. Used wherever a rewrite (an insert/remove path, a
+    /// rotation) needs to move a node's key and value into a new position,
+    /// rather than cloning them the way a shared, persistent handle would
+    /// require.
+    fn take(&mut self, handle: NodeHandle) -> PoolNode<K, V> {
+        self.free.push(handle.index() as u32);
+        self.slots[handle.index()].take().expect("dangling node handle")
+    }
+
+    /// Appends `other`'s nodes onto the end of `self`, rebasing every handle
+    /// `other` minted by the length `self` had before the append, and
+    /// returns that offset. Lets the batching `reduce` step fold two
+    /// batch-local trees' storage into one pool before unioning them, rather
+    /// than copying one side into the other node-by-node.
+    fn absorb(&mut self, other: NodePool<K, V>) -> u32 {
+        let offset = self.slots.len() as u32;
+        self.free.extend(other.free.iter().map(|&index| index + offset));
+        self.slots.extend(other.slots.into_iter().map(|slot| {
+            slot.map(|node| PoolNode {
+                left: node.left.map(|h| h.shifted(offset)),
+                right: node.right.map(|h| h.shifted(offset)),
+                ..node
+            })
+        }));
+        offset
+    }
+}
+
+/// A red-black tree map whose key order is supplied at runtime by `C`,
+/// rather than hard-wired to the key type's own `Ord`. [`RBTreeMap`] is a
+/// thin wrapper around this with `C = `[`OrdComparator`].
+#[derive(Clone)]
+pub struct RBTreeMapBy<K, V, C> {
+    pool: NodePool<K, V>,
+    root: Option<NodeHandle>,
+    cmp: C,
+}
+
+/// The default, `Ord`-ordered red-black tree map.
+#[derive(Clone)]
+pub struct RBTreeMap<K: Ord, V> {
+    inner: RBTreeMapBy<K, V, OrdComparator>,
+}
+
+impl<K, V, C: Comparator<K> + Default> Default for RBTreeMapBy<K, V, C> {
+    fn default() -> Self { Self::with_comparator(C::default()) }
+}
+
+impl<K, V, C: Comparator<K>> RBTreeMapBy<K, V, C> {
+    pub fn with_comparator(cmp: C) -> Self {
+        Self { pool: NodePool::new(), root: None, cmp }
     }
 
     pub fn insert(&mut self, key: K, value: V) {
-        self.root = Some(Box::new(Self::balance(Self::insert_into(self.root.take(), key, value))));
-    }
-
-    fn insert_into(node: Option<Box<Node<K, V>>>, key: K, value: V) -> Node<K, V> {
-        match node {
-            None => Node { color: Color::Red, key, value, left: None, right: None },
-            Some(node) => {
-                let node = *node;
-                if key < node.key {
-                    Node { left: Some(Box::new(Self::insert_into(node.left, key, value))), ..node }
-                } else if key > node.key {
-                    Node { right: Some(Box::new(Self::insert_into(node.right, key, value))), ..node }
-                } else {
-                    Node { value, ..node }
+        let (node, target) = Self::insert_into(&mut self.pool, &self.cmp, self.root.take(), key, value);
+        let (node, _) = Self::balance(&mut self.pool, node, target);
+        self.root = Some(self.pool.alloc(node));
+    }
+
+    /// Builds the post-insert subtree rooted here, alongside `target`: the
+    /// handle the inserted/updated key now lives at, or `None` if it's still
+    /// the returned (not-yet-allocated) node itself. Lets [`VacantEntry`]
+    /// locate its key's final slot for free off the insert it already pays
+    /// for, instead of re-descending with [`Self::find_mut`] afterward.
+    fn insert_into(pool: &mut NodePool<K, V>, cmp: &C, handle: Option<NodeHandle>, key: K, value: V) -> (PoolNode<K, V>, Option<NodeHandle>) {
+        match handle {
+            None => (PoolNode { color: Color::Red, key, value, left: None, right: None }, None),
+            Some(handle) => {
+                let node = pool.take(handle);
+                match cmp.cmp(&key, &node.key) {
+                    Ordering::Less => {
+                        let (left, target) = Self::insert_into(pool, cmp, node.left, key, value);
+                        let left = pool.alloc(left);
+                        (PoolNode { left: Some(left), ..node }, Some(target.unwrap_or(left)))
+                    }
+                    Ordering::Greater => {
+                        let (right, target) = Self::insert_into(pool, cmp, node.right, key, value);
+                        let right = pool.alloc(right);
+                        (PoolNode { right: Some(right), ..node }, Some(target.unwrap_or(right)))
+                    }
+                    Ordering::Equal => (PoolNode { value, ..node }, None),
                 }
             }
         }
     }
 
     pub fn get(&self, key: &K) -> Option<&V> {
-        self.find(&self.root, key)
+        Self::find(&self.pool, &self.cmp, self.root, key)
     }
 
-    fn find<'a>(&'a self, node: &'a Option<Box<Node<K, V>>>, key: &K) -> Option<&V> {
-        node.as_ref().and_then(|node| {
-            if key < &node.key {
-                self.find(&node.left, key)
-            } else if key > &node.key {
-                self.find(&node.right, key)
-            } else {
-                Some(&node.value)
+    fn find<'a>(pool: &'a NodePool<K, V>, cmp: &C, handle: Option<NodeHandle>, key: &K) -> Option<&'a V> {
+        let node = pool.get(handle?);
+        match cmp.cmp(key, &node.key) {
+            Ordering::Less => Self::find(pool, cmp, node.left, key),
+            Ordering::Greater => Self::find(pool, cmp, node.right, key),
+            Ordering::Equal => Some(&node.value),
+        }
+    }
+
+    fn find_mut<'a>(pool: &'a mut NodePool<K, V>, cmp: &C, handle: Option<NodeHandle>, key: &K) -> Option<&'a mut V> {
+        let handle = handle?;
+        match cmp.cmp(key, &pool.get(handle).key) {
+            Ordering::Less => {
+                let left = pool.get(handle).left;
+                Self::find_mut(pool, cmp, left, key)
+            }
+            Ordering::Greater => {
+                let right = pool.get(handle).right;
+                Self::find_mut(pool, cmp, right, key)
             }
-        })
+            Ordering::Equal => Some(&mut pool.get_mut(handle).value),
+        }
+    }
+
+    /// A handle onto `key`'s slot, for a read-modify-write without a
+    /// separate `get` call.
+    ///
+    /// Borrow-checking a single traversal that either returns a `&mut V`
+    /// into the tree or falls through to keep using `self` doesn't work
+    /// here (the `&mut V`'s lifetime would have to be decided before the
+    /// branch is known), so this does a read-only lookup first to decide
+    /// which arm applies, then borrows `self` accordingly. The vacant case
+    /// doesn't re-find its key after inserting: [`Self::insert_into`] and
+    /// [`Self::balance`] both hand back the handle the key ended up at
+    /// alongside the rebuilt subtree, so [`VacantEntry::insert`] only pays
+    /// for the one descent its insert already needed.
+    pub fn entry(&mut self, key: K) -> Entry<'_, K, V, C> {
+        if Self::find(&self.pool, &self.cmp, self.root, &key).is_some() {
+            let cmp = &self.cmp;
+            let root = self.root;
+            let value = Self::find_mut(&mut self.pool, cmp, root, &key).expect("checked present above");
+            Entry::Occupied(OccupiedEntry { value })
+        } else {
+            Entry::Vacant(VacantEntry { map: self, key })
+        }
+    }
+
+    /// In-order iterator over every entry.
+    pub fn iter(&self) -> Iter<'_, K, V> {
+        Iter::new(&self.pool, self.root)
+    }
+
+    /// The entry with the nearest key strictly greater than `key`, if any.
+    pub fn above(&self, key: &K) -> Option<(&K, &V)> {
+        Self::above_in(&self.pool, &self.cmp, self.root, key)
+    }
+
+    fn above_in<'a>(pool: &'a NodePool<K, V>, cmp: &C, handle: Option<NodeHandle>, key: &K) -> Option<(&'a K, &'a V)> {
+        let node = pool.get(handle?);
+        if cmp.cmp(&node.key, key) == Ordering::Greater {
+            Self::above_in(pool, cmp, node.left, key).or(Some((&node.key, &node.value)))
+        } else {
+            Self::above_in(pool, cmp, node.right, key)
+        }
+    }
+
+    /// The entry with the nearest key strictly less than `key`, if any.
+    pub fn below(&self, key: &K) -> Option<(&K, &V)> {
+        Self::below_in(&self.pool, &self.cmp, self.root, key)
+    }
+
+    fn below_in<'a>(pool: &'a NodePool<K, V>, cmp: &C, handle: Option<NodeHandle>, key: &K) -> Option<(&'a K, &'a V)> {
+        let node = pool.get(handle?);
+        if cmp.cmp(&node.key, key) == Ordering::Less {
+            Self::below_in(pool, cmp, node.right, key).or(Some((&node.key, &node.value)))
+        } else {
+            Self::below_in(pool, cmp, node.left, key)
+        }
     }
 
     pub fn remove(&mut self, key: &K) -> Option<V> {
-        let (root, value) = Self::remove_node(self.root.take(), key);
-        self.root = root.map(|node| Box::new(Self::balance(*node)));
+        let (root, value) = Self::remove_node(&mut self.pool, &self.cmp, self.root.take(), key);
+        self.root = root.map(|handle| {
+            let node = self.pool.take(handle);
+            let (node, _) = Self::balance(&mut self.pool, node, None);
+            self.pool.alloc(node)
+        });
         value
     }
 
-    fn remove_node(node: Option<Box<Node<K, V>>>, key: &K) -> (Option<Box<Node<K, V>>>, Option<V>) {
-        match node {
-            None => (node, None),
-            Some(node) => {
-                let Node {
+    fn remove_node(pool: &mut NodePool<K, V>, cmp: &C, handle: Option<NodeHandle>, key: &K) -> (Option<NodeHandle>, Option<V>) {
+        match handle {
+            None => (None, None),
+            Some(handle) => {
+                let PoolNode {
                     left: node_left, right: node_right,
                     key: node_key, value: node_value, color: node_color,
-                } = *node;
-                if key < &node_key {
-                    let (updated_left, value) = Self::remove_node(node_left, key);
-                    (Some(Box::new(Node {
-                        left: updated_left, right: node_right,
-                        color: node_color, key: node_key, value: node_value
-                    })), value)
-                } else if key > &node_key {
-                    let (updated_right, value) = Self::remove_node(node_right, key);
-                    (Some(Box::new(Node {
-                        left: node_left, right: updated_right,
-                        color: node_color, key: node_key, value: node_value
-                    })), value)
-                } else {
-                    match (node_left, node_right) {
-                        (None, None) => (None, Some(node_value)),
-                        (Some(left), None) => (Some(left), Some(node_value)),
-                        (None, Some(right)) => (Some(right), Some(node_value)),
-                        (Some(left), Some(right)) => {
-                            let mut left = Some(left);
-                            let pred = Self::get_highest(&mut left).unwrap();
-                            let left = Self::remove_from_pred(left, &pred.key);
-                            (Some(Box::new(Node {
-                                left, right: Some(right), color: node_color,
-                                key: pred.key, value: pred.value
-                            })), Some(node_value))
+                } = pool.take(handle);
+                match cmp.cmp(key, &node_key) {
+                    Ordering::Less => {
+                        let (updated_left, value) = Self::remove_node(pool, cmp, node_left, key);
+                        let handle = pool.alloc(PoolNode {
+                            left: updated_left, right: node_right,
+                            color: node_color, key: node_key, value: node_value,
+                        });
+                        (Some(handle), value)
+                    }
+                    Ordering::Greater => {
+                        let (updated_right, value) = Self::remove_node(pool, cmp, node_right, key);
+                        let handle = pool.alloc(PoolNode {
+                            left: node_left, right: updated_right,
+                            color: node_color, key: node_key, value: node_value,
+                        });
+                        (Some(handle), value)
+                    }
+                    Ordering::Equal => {
+                        match (node_left, node_right) {
+                            (None, None) => (None, Some(node_value)),
+                            (Some(left), None) => (Some(left), Some(node_value)),
+                            (None, Some(right)) => (Some(right), Some(node_value)),
+                            (Some(left), Some(right)) => {
+                                let mut left = Some(left);
+                                let pred = Self::get_highest(pool, &mut left).unwrap();
+                                let left = Self::remove_from_pred(pool, cmp, left, &pred.key);
+                                let handle = pool.alloc(PoolNode {
+                                    left, right: Some(right), color: node_color,
+                                    key: pred.key, value: pred.value,
+                                });
+                                (Some(handle), Some(node_value))
+                            }
                         }
                     }
                 }
@@ -113,167 +329,671 @@ impl<K: PartialOrd, V> RBTreeMap<K, V> {
         }
     }
 
-    fn remove_from_pred(node: Option<Box<Node<K, V>>>, pred_key: &K) -> Option<Box<Node<K, V>>> {
-        match node {
+    fn remove_from_pred(pool: &mut NodePool<K, V>, cmp: &C, handle: Option<NodeHandle>, pred_key: &K) -> Option<NodeHandle> {
+        match handle {
             None => None,
-            Some(node) => {
-                let node = *node;
-                if &node.key != pred_key {
-                    Some(Box::new(Node {
-                        left: Self::remove_from_pred(node.left, pred_key),
-                        ..node
-                    }))
-                } else { node.left }
+            Some(handle) => {
+                let node = pool.take(handle);
+                if cmp.cmp(&node.key, pred_key) != Ordering::Equal {
+                    let left = Self::remove_from_pred(pool, cmp, node.left, pred_key);
+                    Some(pool.alloc(PoolNode { left, ..node }))
+                } else {
+                    node.left
+                }
             }
         }
     }
 
-    fn get_highest(node: &mut Option<Box<Node<K, V>>>) -> Option<Node<K, V>> {
-        if let Some(ref mut node_ref) = node {
-            if node_ref.right.is_none() {
-                let left_node = node_ref.left.take();
-                match left_node { 
-                    Some(left_node) => node.replace(left_node),
-                    None => node.take(),
-                }.map(|node| *node)
-            } else {
-                Self::get_highest(&mut node_ref.right)
+    fn get_highest(pool: &mut NodePool<K, V>, handle: &mut Option<NodeHandle>) -> Option<PoolNode<K, V>> {
+        let h = (*handle)?;
+        if pool.get(h).right.is_none() {
+            let left = pool.get(h).left;
+            let removed = pool.take(h);
+            *handle = left;
+            Some(removed)
+        } else {
+            let mut right = pool.get(h).right;
+            let removed = Self::get_highest(pool, &mut right);
+            pool.get_mut(h).right = right;
+            removed
+        }
+    }
+
+    fn black_height(pool: &NodePool<K, V>, handle: Option<NodeHandle>) -> usize {
+        match handle {
+            None => 0,
+            Some(handle) => Self::node_black_height(pool, handle),
+        }
+    }
+
+    fn node_black_height(pool: &NodePool<K, V>, handle: NodeHandle) -> usize {
+        let node = pool.get(handle);
+        let h = Self::black_height(pool, node.left);
+        match node.color {
+            Color::Black => h + 1,
+            Color::Red => h,
+        }
+    }
+
+    /// Joins `left`, `key`/`value` and `right` into a single red-black tree.
+    ///
+    /// Precondition: every key in `left` is strictly less than `key`, which
+    /// is strictly less than every key in `right`. Descends only as many
+    /// levels as the black-heights of `left` and `right` differ by, but
+    /// black-heights are not stored: each level recomputes them by walking a
+    /// left spine, so the cost is logarithmic-squared rather than linear in
+    /// the trees' size.
+    fn join(pool: &mut NodePool<K, V>, left: Option<NodeHandle>, key: K, value: V, right: Option<NodeHandle>) -> Option<NodeHandle> {
+        let (left_height, right_height) = (Self::black_height(pool, left), Self::black_height(pool, right));
+        let mut node = if left_height == right_height {
+            PoolNode { color: Color::Black, key, value, left, right }
+        } else if left_height > right_height {
+            Self::join_right(pool, left, key, value, right)
+        } else {
+            Self::join_left(pool, left, key, value, right)
+        };
+        // The recursive helpers may hand back a red root; the top of a
+        // red-black tree must always be black.
+        node.color = Color::Black;
+        Some(pool.alloc(node))
+    }
+
+    /// `left` is taller than `right`: descend `left`'s right spine until the
+    /// black-heights line up, splice `key`/`value`/`right` in as a red node,
+    /// then rebalance back up using the existing insertion `balance`. A
+    /// missing subtree counts as a black leaf of height zero, same as
+    /// everywhere else in this file.
+    fn join_right(pool: &mut NodePool<K, V>, left: Option<NodeHandle>, key: K, value: V, right: Option<NodeHandle>) -> PoolNode<K, V> {
+        let left_is_black = left.is_none_or(|h| matches!(pool.get(h).color, Color::Black));
+        if left_is_black && Self::black_height(pool, left) == Self::black_height(pool, right) {
+            return PoolNode { color: Color::Red, left, key, value, right };
+        }
+        let PoolNode { color, key: left_key, value: left_value, left: left_left, right: left_right } = pool.take(left.unwrap());
+        let spliced = Self::join_right(pool, left_right, key, value, right);
+        let spliced = pool.alloc(spliced);
+        let node = PoolNode { color, key: left_key, value: left_value, left: left_left, right: Some(spliced) };
+        if matches!(color, Color::Black) { Self::balance(pool, node, None).0 } else { node }
+    }
+
+    /// Mirror of [`Self::join_right`] for when `right` is the taller side.
+    fn join_left(pool: &mut NodePool<K, V>, left: Option<NodeHandle>, key: K, value: V, right: Option<NodeHandle>) -> PoolNode<K, V> {
+        let right_is_black = right.is_none_or(|h| matches!(pool.get(h).color, Color::Black));
+        if right_is_black && Self::black_height(pool, right) == Self::black_height(pool, left) {
+            return PoolNode { color: Color::Red, left, key, value, right };
+        }
+        let PoolNode { color, key: right_key, value: right_value, left: right_left, right: right_right } = pool.take(right.unwrap());
+        let spliced = Self::join_left(pool, left, key, value, right_left);
+        let spliced = pool.alloc(spliced);
+        let node = PoolNode { color, key: right_key, value: right_value, left: Some(spliced), right: right_right };
+        if matches!(color, Color::Black) { Self::balance(pool, node, None).0 } else { node }
+    }
+
+    /// Splits `node` around `key`, returning everything strictly less than
+    /// `key`, the value stored at `key` (if any), and everything strictly
+    /// greater. Mirrors [`Self::join`], cutting along the search path and
+    /// re-joining the pieces left behind on the way back up.
+    fn split(pool: &mut NodePool<K, V>, cmp: &C, handle: Option<NodeHandle>, key: &K) -> (Option<NodeHandle>, Option<V>, Option<NodeHandle>) {
+        match handle {
+            None => (None, None, None),
+            Some(handle) => {
+                let PoolNode { key: node_key, value: node_value, left, right, .. } = pool.take(handle);
+                match cmp.cmp(key, &node_key) {
+                    Ordering::Less => {
+                        let (less, present, between) = Self::split(pool, cmp, left, key);
+                        (less, present, Self::join(pool, between, node_key, node_value, right))
+                    }
+                    Ordering::Greater => {
+                        let (between, present, greater) = Self::split(pool, cmp, right, key);
+                        (Self::join(pool, left, node_key, node_value, between), present, greater)
+                    }
+                    Ordering::Equal => (left, Some(node_value), right),
+                }
             }
-        } else { None }
-    }
-
-    fn balance(node: Node<K, V>) -> Node<K, V> {
-        match node {
-            Node {
-                color: Color::Black, key: right_key, value: right_value,
-                left: Some(box Node {
-                    color: Color::Red, key: top_key, value: top_value,
-                    left: Some(box Node {
-                        color: Color::Red, key: left_key, value: left_value,
-                        left: left_left, right: left_right,
-                    }), right: right_left,
-                }), right: right_right,
-            } => Node {
-                color: Color::Red, key: top_key, value: top_value,
-                left: Some(Box::new(Node {
-                    color: Color::Black, key: left_key, value: left_value,
-                    left: left_left, right: left_right,
-                })),
-                right: Some(Box::new(Node {
-                    color: Color::Black, key: right_key, value: right_value,
-                    left: right_left, right: right_right,
-                })),
-            },
-            Node {
-                color: Color::Black, key: right_key, value: right_value,
-                left: Some(box Node {
-                    color: Color::Red, key: left_key, value: left_value,
-                    left: left_left, right: Some(box Node {
-                        color: Color::Red, key: top_key, value: top_value,
-                        left: left_right, right: right_left,
-                    }),
-                }), right: right_right,
-            } => Node {
-                color: Color::Red, key: top_key, value: top_value,
-                left: Some(Box::new(Node {
-                    color: Color::Black, key: left_key,
-                    value: left_value, left: left_left, right: left_right,
-                })),
-                right: Some(Box::new(Node {
-                    color: Color::Black, key: right_key, value: right_value,
-                    left: right_left, right: right_right,
-                })),
-            },
-            Node {
-                color: Color::Black, key: left_key, value: left_value,
-                left: left_left, right: Some(box Node {
-                color: Color::Red, key: right_key, value: right_value,
-                left: Some(box Node {
-                    color: Color::Red, key: top_key, value: top_value,
-                    left: left_right, right: right_left,
-                }), right: right_right,
-            }),
-            } => Node {
-                color: Color::Red, key: top_key, value: top_value,
-                left: Some(Box::new(Node {
-                    color: Color::Black, key: left_key, value: left_value,
-                    left: left_left, right: left_right,
-                })),
-                right: Some(Box::new(Node {
-                    color: Color::Black, key: right_key, value: right_value,
-                    left: right_left, right: right_right,
-                })),
-            },
-            Node {
-                color: Color::Black, key: left_key, value: left_value,
-                left: left_left, right: Some(box Node {
-                color: Color::Red, key: top_key, value: top_value,
-                left: left_right, right: Some(box Node {
-                    color: Color::Red, key: right_key, value: right_value,
-                    left: right_left, right: right_right,
-                }),
-            }),
-            } => Node {
-                color: Color::Red, key: top_key, value: top_value,
-                left: Some(Box::new(Node {
-                    color: Color::Black, key: left_key, value: left_value,
-                    left: left_left, right: left_right,
-                })),
-                right: Some(Box::new(Node {
-                    color: Color::Black, key: right_key, value: right_value,
-                    left: right_left, right: right_right,
-                })),
-            },
-            _ => node,
         }
     }
+
+    /// Ordered union of `left` and `right`. On a key present in both trees,
+    /// `resolve(left_value, right_value)` decides which value survives.
+    ///
+    /// `left` and `right` must be handles into the same `pool`; the caller
+    /// is responsible for folding two batch-local trees' pools into one (see
+    /// [`NodePool::absorb`]) before calling this.
+    fn union(pool: &mut NodePool<K, V>, cmp: &C, left: Option<NodeHandle>, right: Option<NodeHandle>, resolve: &impl Fn(V, V) -> V) -> Option<NodeHandle> {
+        match (left, right) {
+            (None, right) => right,
+            (left, None) => left,
+            (Some(left), right) => {
+                let PoolNode { key, value, left: left_left, right: left_right, .. } = pool.take(left);
+                let (right_less, right_present, right_greater) = Self::split(pool, cmp, right, &key);
+                let value = match right_present {
+                    Some(right_value) => resolve(value, right_value),
+                    None => value,
+                };
+                // These two recursions cover disjoint key ranges and do not
+                // depend on each other; here they run one after the other.
+                // Parallelism comes from `utils::parallel_reduce` calling
+                // `reduce` on separate pairs of per-op trees.
+                let merged_left = Self::union(pool, cmp, left_left, right_less, resolve);
+                let merged_right = Self::union(pool, cmp, left_right, right_greater, resolve);
+                Self::join(pool, merged_left, key, value, merged_right)
+            }
+        }
+    }
+
+    /// Rewrites `target` to follow a key's content across a rotation that
+    /// moved it from `old` to `new`, or from being the as-yet-unallocated
+    /// `node` argument itself (`target == None`) to `became_node`. A target
+    /// naming neither `old` handle is untouched by this rotation and passes
+    /// through unchanged.
+    fn redirect_target(
+        target: Option<NodeHandle>,
+        became_node: NodeHandle,
+        outer_old: NodeHandle,
+        inner_old: NodeHandle,
+        inner_new: NodeHandle,
+    ) -> Option<NodeHandle> {
+        match target {
+            None => Some(became_node),
+            Some(h) if h == outer_old => None,
+            Some(h) if h == inner_old => Some(inner_new),
+            other => other,
+        }
+    }
+
+    /// Restores the red-black invariant at `node`'s root, given that
+    /// `target` names the handle (or `None` for `node` itself, not yet
+    /// allocated) holding a key whose location a caller cares about; returns
+    /// the rebalanced node alongside `target` updated to follow that key
+    /// through any rotation performed here. Callers that don't care about a
+    /// particular key can pass `None` and discard the second element.
+    fn balance(pool: &mut NodePool<K, V>, node: PoolNode<K, V>, target: Option<NodeHandle>) -> (PoolNode<K, V>, Option<NodeHandle>) {
+        if !matches!(node.color, Color::Black) {
+            return (node, target);
+        }
+
+        if let Some(left) = node.left {
+            if matches!(pool.get(left).color, Color::Red) {
+                // Left-left: black root, red left child, red left-left grandchild.
+                if let Some(left_left) = pool.get(left).left {
+                    if matches!(pool.get(left_left).color, Color::Red) {
+                        let PoolNode { key: top_key, value: top_value, right: right_left, .. } = pool.take(left);
+                        let PoolNode { key: left_key, value: left_value, left: left_left_left, right: left_right, .. } = pool.take(left_left);
+                        let PoolNode { key: right_key, value: right_value, right: right_right, .. } = node;
+                        let new_left = pool.alloc(PoolNode { color: Color::Black, key: left_key, value: left_value, left: left_left_left, right: left_right });
+                        let new_right = pool.alloc(PoolNode { color: Color::Black, key: right_key, value: right_value, left: right_left, right: right_right });
+                        let target = Self::redirect_target(target, new_right, left, left_left, new_left);
+                        return (PoolNode { color: Color::Red, key: top_key, value: top_value, left: Some(new_left), right: Some(new_right) }, target);
+                    }
+                }
+                // Left-right: black root, red left child, red left-right grandchild.
+                if let Some(left_right) = pool.get(left).right {
+                    if matches!(pool.get(left_right).color, Color::Red) {
+                        let PoolNode { key: top_key, value: top_value, left: left_right_left, right: right_left, .. } = pool.take(left_right);
+                        let PoolNode { key: left_key, value: left_value, left: left_left, .. } = pool.take(left);
+                        let PoolNode { key: right_key, value: right_value, right: right_right, .. } = node;
+                        let new_left = pool.alloc(PoolNode { color: Color::Black, key: left_key, value: left_value, left: left_left, right: left_right_left });
+                        let new_right = pool.alloc(PoolNode { color: Color::Black, key: right_key, value: right_value, left: right_left, right: right_right });
+                        let target = Self::redirect_target(target, new_right, left_right, left, new_left);
+                        return (PoolNode { color: Color::Red, key: top_key, value: top_value, left: Some(new_left), right: Some(new_right) }, target);
+                    }
+                }
+            }
+        }
+
+        if let Some(right) = node.right {
+            if matches!(pool.get(right).color, Color::Red) {
+                // Right-left: black root, red right child, red right-left grandchild.
+                if let Some(right_left) = pool.get(right).left {
+                    if matches!(pool.get(right_left).color, Color::Red) {
+                        let PoolNode { key: top_key, value: top_value, left: left_right, right: right_left_right, .. } = pool.take(right_left);
+                        let PoolNode { key: right_key, value: right_value, right: right_right, .. } = pool.take(right);
+                        let PoolNode { key: left_key, value: left_value, left: left_left, .. } = node;
+                        let new_left = pool.alloc(PoolNode { color: Color::Black, key: left_key, value: left_value, left: left_left, right: left_right });
+                        let new_right = pool.alloc(PoolNode { color: Color::Black, key: right_key, value: right_value, left: right_left_right, right: right_right });
+                        let target = Self::redirect_target(target, new_left, right_left, right, new_right);
+                        return (PoolNode { color: Color::Red, key: top_key, value: top_value, left: Some(new_left), right: Some(new_right) }, target);
+                    }
+                }
+                // Right-right: black root, red right child, red right-right grandchild.
+                if let Some(right_right) = pool.get(right).right {
+                    if matches!(pool.get(right_right).color, Color::Red) {
+                        let PoolNode { key: right_key, value: right_value, left: right_right_left, right: right_right_right, .. } = pool.take(right_right);
+                        let PoolNode { key: top_key, value: top_value, left: left_right, .. } = pool.take(right);
+                        let PoolNode { key: left_key, value: left_value, left: left_left, .. } = node;
+                        let new_left = pool.alloc(PoolNode { color: Color::Black, key: left_key, value: left_value, left: left_left, right: left_right });
+                        let new_right = pool.alloc(PoolNode { color: Color::Black, key: right_key, value: right_value, left: right_right_left, right: right_right_right });
+                        let target = Self::redirect_target(target, new_left, right, right_right, new_right);
+                        return (PoolNode { color: Color::Red, key: top_key, value: top_value, left: Some(new_left), right: Some(new_right) }, target);
+                    }
+                }
+            }
+        }
+
+        (node, target)
+    }
+}
+
+impl<K: Ord, V> RBTreeMapBy<K, V, OrdComparator> {
+    /// In-order iterator over the entries whose key falls within `bounds`.
+    ///
+    /// Restricted to [`OrdComparator`]: `bounds`'s `start`/`end` are fixed by
+    /// the literal range expression in `K`'s own `Ord` order, so pruning by a
+    /// `cmp` that disagrees with `K::Ord` (a reversed or otherwise
+    /// differently-ordered comparator) would silently invert which entries
+    /// come out, rather than panic or document the mismatch. Use
+    /// [`RBTreeMap`], or an `RBTreeMapBy` explicitly parameterized with
+    /// `OrdComparator`, to call this.
+    pub fn range<R: RangeBounds<K>>(&self, bounds: R) -> Range<'_, K, V, R, OrdComparator> {
+        Range::new(&self.pool, self.root, bounds, &self.cmp)
+    }
+}
+
+impl<K: Ord, V> Default for RBTreeMap<K, V> {
+    fn default() -> Self { Self::new() }
+}
+
+impl<K: Ord, V> RBTreeMap<K, V> {
+    pub fn new() -> Self {
+        Self { inner: RBTreeMapBy::with_comparator(OrdComparator) }
+    }
+
+    pub fn insert(&mut self, key: K, value: V) {
+        self.inner.insert(key, value)
+    }
+
+    pub fn get(&self, key: &K) -> Option<&V> {
+        self.inner.get(key)
+    }
+
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        self.inner.remove(key)
+    }
+
+    /// A view into `key`'s slot, for a read-modify-write without a separate
+    /// `get` call. See [`RBTreeMapBy::entry`] for how many descents it makes.
+    pub fn entry(&mut self, key: K) -> Entry<'_, K, V, OrdComparator> {
+        self.inner.entry(key)
+    }
+
+    /// In-order iterator over every entry.
+    pub fn iter(&self) -> Iter<'_, K, V> {
+        self.inner.iter()
+    }
+
+    /// In-order iterator over the entries whose key falls within `bounds`.
+    pub fn range<R: RangeBounds<K>>(&self, bounds: R) -> Range<'_, K, V, R, OrdComparator> {
+        self.inner.range(bounds)
+    }
+
+    /// The entry with the nearest key strictly greater than `key`, if any.
+    pub fn above(&self, key: &K) -> Option<(&K, &V)> {
+        self.inner.above(key)
+    }
+
+    /// The entry with the nearest key strictly less than `key`, if any.
+    pub fn below(&self, key: &K) -> Option<(&K, &V)> {
+        self.inner.below(key)
+    }
+}
+
+/// In-order iterator over a tree's entries.
+///
+/// Walks an explicit stack of node handles rather than recursing, so the
+/// traversal can be paused and resumed lazily instead of building the whole
+/// sequence up front.
+pub struct Iter<'a, K, V> {
+    pool: &'a NodePool<K, V>,
+    stack: Vec<NodeHandle>,
+}
+
+impl<'a, K, V> Iter<'a, K, V> {
+    fn new(pool: &'a NodePool<K, V>, root: Option<NodeHandle>) -> Self {
+        let mut iter = Iter { pool, stack: Vec::new() };
+        iter.push_left_spine(root);
+        iter
+    }
+
+    fn push_left_spine(&mut self, mut handle: Option<NodeHandle>) {
+        while let Some(h) = handle {
+            self.stack.push(h);
+            handle = self.pool.get(h).left;
+        }
+    }
+}
+
+impl<'a, K, V> Iterator for Iter<'a, K, V> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let handle = self.stack.pop()?;
+        let node = self.pool.get(handle);
+        self.push_left_spine(node.right);
+        Some((&node.key, &node.value))
+    }
+}
+
+/// In-order iterator over a tree's entries within `bounds`, ordered by `C`.
+///
+/// Prunes whole subtrees that fall outside the lower bound while descending,
+/// and stops as soon as a popped key falls outside the upper bound.
+pub struct Range<'a, K, V, R: RangeBounds<K>, C> {
+    pool: &'a NodePool<K, V>,
+    stack: Vec<NodeHandle>,
+    bounds: R,
+    cmp: &'a C,
+}
+
+impl<'a, K, V, R: RangeBounds<K>, C: Comparator<K>> Range<'a, K, V, R, C> {
+    fn new(pool: &'a NodePool<K, V>, root: Option<NodeHandle>, bounds: R, cmp: &'a C) -> Self {
+        let mut range = Range { pool, stack: Vec::new(), bounds, cmp };
+        range.push_left_spine(root);
+        range
+    }
+
+    fn push_left_spine(&mut self, mut handle: Option<NodeHandle>) {
+        while let Some(h) = handle {
+            let node = self.pool.get(h);
+            let below_start = match self.bounds.start_bound() {
+                Bound::Included(start) => self.cmp.cmp(&node.key, start) == Ordering::Less,
+                Bound::Excluded(start) => self.cmp.cmp(&node.key, start) != Ordering::Greater,
+                Bound::Unbounded => false,
+            };
+            if below_start {
+                handle = node.right;
+                continue;
+            }
+            self.stack.push(h);
+            handle = node.left;
+        }
+    }
+
+    fn above_end(&self, key: &K) -> bool {
+        match self.bounds.end_bound() {
+            Bound::Included(end) => self.cmp.cmp(key, end) == Ordering::Greater,
+            Bound::Excluded(end) => self.cmp.cmp(key, end) != Ordering::Less,
+            Bound::Unbounded => false,
+        }
+    }
+}
+
+impl<'a, K, V, R: RangeBounds<K>, C: Comparator<K>> Iterator for Range<'a, K, V, R, C> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let handle = self.stack.pop()?;
+        let node = self.pool.get(handle);
+        if self.above_end(&node.key) {
+            self.stack.clear();
+            return None;
+        }
+        self.push_left_spine(node.right);
+        Some((&node.key, &node.value))
+    }
+}
+
+/// A view into a single key's slot in an [`RBTreeMapBy`], obtained via
+/// [`RBTreeMapBy::entry`].
+pub enum Entry<'a, K, V, C> {
+    Occupied(OccupiedEntry<'a, V>),
+    Vacant(VacantEntry<'a, K, V, C>),
+}
+
+impl<'a, K, V, C: Comparator<K>> Entry<'a, K, V, C> {
+    /// Inserts `default` if the entry is vacant, then returns a mutable
+    /// reference to the value either way.
+    pub fn or_insert(self, default: V) -> &'a mut V {
+        match self {
+            Entry::Occupied(entry) => entry.value,
+            Entry::Vacant(entry) => entry.insert(default),
+        }
+    }
+
+    /// Like [`Self::or_insert`], but only computes the default on a miss.
+    pub fn or_insert_with<F: FnOnce() -> V>(self, default: F) -> &'a mut V {
+        match self {
+            Entry::Occupied(entry) => entry.value,
+            Entry::Vacant(entry) => entry.insert(default()),
+        }
+    }
+
+    /// Runs `f` against the value if the entry is already occupied, leaving
+    /// a vacant entry untouched. Either way, returns `self` for chaining
+    /// into `or_insert`/`or_insert_with`.
+    pub fn and_modify<F: FnOnce(&mut V)>(self, f: F) -> Self {
+        match self {
+            Entry::Occupied(entry) => {
+                f(entry.value);
+                Entry::Occupied(entry)
+            }
+            Entry::Vacant(entry) => Entry::Vacant(entry),
+        }
+    }
+}
+
+pub struct OccupiedEntry<'a, V> {
+    value: &'a mut V,
+}
+
+pub struct VacantEntry<'a, K, V, C> {
+    map: &'a mut RBTreeMapBy<K, V, C>,
+    key: K,
+}
+
+impl<'a, K, V, C: Comparator<K>> VacantEntry<'a, K, V, C> {
+    /// Inserts `value` under this entry's key and returns a mutable
+    /// reference to it, off the same descent the insert itself performs.
+    pub fn insert(self, value: V) -> &'a mut V {
+        let VacantEntry { map, key } = self;
+        let (node, target) = RBTreeMapBy::<K, V, C>::insert_into(&mut map.pool, &map.cmp, map.root.take(), key, value);
+        let (node, target) = RBTreeMapBy::<K, V, C>::balance(&mut map.pool, node, target);
+        let root = map.pool.alloc(node);
+        map.root = Some(root);
+        &mut map.pool.get_mut(target.unwrap_or(root)).value
+    }
 }
 
-#[derive(Debug)]
 pub enum RBTreeMapOp<K, V> {
     Insert(K, V),
     Get(K),
     Remove(K),
+    Range(Bound<K>, Bound<K>),
+    Above(K),
+    Below(K),
+    /// Atomic compute-if-present/compute-if-absent: `f` receives the key's
+    /// current value (or `None`) and returns the value to store. Several
+    /// `Compute`s on the same key within one batch chain in batch order, so
+    /// each sees the value the previous one produced.
+    Compute(K, Box<dyn FnOnce(Option<V>) -> V + Send>),
 }
 
-impl<K: PartialOrd, V: Send + 'static> BatchedOp for RBTreeMapOp<K, V> {
-    type Res = Option<V>;
+/// Result of a [`RBTreeMapOp`], shared across all variants since a batch
+/// mixes point reads, navigation queries and mutations together.
+#[derive(Debug)]
+pub enum RBTreeMapRes<K, V> {
+    Value(Option<V>),
+    Entry(Option<(K, V)>),
+    Entries(Vec<(K, V)>),
+}
+
+impl<K: Send + 'static, V: Send + 'static> BatchedOp for RBTreeMapOp<K, V> {
+    type Res = RBTreeMapRes<K, V>;
 }
 
+/// A single batch-local transformation of a key's value, tagged with its
+/// position in the batch. `Insert`/`Remove` become the constant transforms
+/// `|_| Some(v)`/`|_| None`; `Compute` becomes a transform that reads
+/// whatever precedes it. Composing two slots for the same key applies the
+/// earlier one's transform first and feeds its result into the later one's,
+/// so `Compute`s resolve in batch order however `union` happens to pair the
+/// per-op trees up; this also generalizes the old last-writer-wins
+/// behavior, since an `Insert`/`Remove` transform ignores its input anyway.
+type Slot<V> = Box<dyn FnOnce(Option<V>) -> Option<V> + Send>;
+
 impl<K, V> Batched for RBTreeMap<K, V>
 where
-    K: PartialOrd + Debug + Send + 'static,
-    V: Send + Debug + 'static,
+    K: Ord + Debug + Clone + Send + 'static,
+    V: Send + Debug + Clone + 'static,
 {
     type Op = RBTreeMapOp<K, V>;
 
     fn init() -> Self { Self::new() }
 
     async fn run_batch(&mut self, ops: Vec<WrappedOp<Self::Op>>) {
-        fn reduce<K, V>(l: RBTreeMap<K, V>, r: RBTreeMap<K, V>) -> RBTreeMap<K, V>
+        fn reduce<K, V>(
+            l: RBTreeMapBy<K, (usize, Slot<V>), OrdComparator>,
+            r: RBTreeMapBy<K, (usize, Slot<V>), OrdComparator>,
+        ) -> RBTreeMapBy<K, (usize, Slot<V>), OrdComparator>
         where
-            K: PartialOrd + Debug + Send + 'static,
+            K: Ord + Debug + Send + 'static,
             V: Send + Debug + 'static,
-        { 
-            todo!()
+        {
+            let RBTreeMapBy { mut pool, root: l_root, .. } = l;
+            let RBTreeMapBy { pool: r_pool, root: r_root, .. } = r;
+            // Fold both batch-local trees' storage into one pool first, so
+            // `union` below can operate over handles from a single arena
+            // instead of copying one side into the other node-by-node.
+            let offset = pool.absorb(r_pool);
+            let r_root = r_root.map(|handle| handle.shifted(offset));
+            let root = RBTreeMapBy::<K, (usize, Slot<V>), OrdComparator>::union(
+                &mut pool, &OrdComparator, l_root, r_root,
+                &|a: (usize, Slot<V>), b: (usize, Slot<V>)| {
+                    let (earlier, later) = if a.0 <= b.0 { (a, b) } else { (b, a) };
+                    let index = later.0;
+                    (index, Box::new(move |old| (later.1)((earlier.1)(old))))
+                },
+            );
+            RBTreeMapBy { pool, root, cmp: OrdComparator }
         }
-        
-        let map = move |op: WrappedOp<RBTreeMapOp<K, V>>| -> RBTreeMap<K, V> {
+
+        let map = |op: WrappedOp<RBTreeMapOp<K, (usize, Slot<V>)>>| -> RBTreeMapBy<K, (usize, Slot<V>), OrdComparator> {
             match op.0 {
-                RBTreeMapOp::Insert(key, value) => todo!(),
-                RBTreeMapOp::Get(_) => todo!(),
-                RBTreeMapOp::Remove(_) => todo!()
+                RBTreeMapOp::Insert(key, tagged) => {
+                    let mut singleton = RBTreeMapBy::with_comparator(OrdComparator);
+                    singleton.insert(key, tagged);
+                    singleton
+                }
+                _ => unreachable!("a batch's merge ops are always tagged inserts"),
             }
         };
-        utils::parallel_reduce(ops, reduce, map).await;
-        // TODO: merge the RBTree
-        todo!()
+
+        enum ReadQuery<K> {
+            Get(K),
+            Range(Bound<K>, Bound<K>),
+            Above(K),
+            Below(K),
+        }
+
+        type ReadBuffer<K, V> = Vec<(ReadQuery<K>, Box<dyn FnOnce(RBTreeMapRes<K, V>) + Send>)>;
+        type MutationBuffer<K, V> = Vec<WrappedOp<RBTreeMapOp<K, (usize, Slot<V>)>>>;
+
+        // Phase one: split the batch into mutations (to be merged into one
+        // tree and folded in below) and reads (buffered until the merge has
+        // landed, so a query observes its own batch's writes).
+        let mut reads: ReadBuffer<K, V> = Vec::new();
+        let mutations: MutationBuffer<K, V> = ops
+            .into_iter()
+            .enumerate()
+            .filter_map(|(index, op)| match op.0 {
+                RBTreeMapOp::Get(key) => {
+                    reads.push((ReadQuery::Get(key), op.1));
+                    None
+                }
+                RBTreeMapOp::Range(start, end) => {
+                    reads.push((ReadQuery::Range(start, end), op.1));
+                    None
+                }
+                RBTreeMapOp::Above(key) => {
+                    reads.push((ReadQuery::Above(key), op.1));
+                    None
+                }
+                RBTreeMapOp::Below(key) => {
+                    reads.push((ReadQuery::Below(key), op.1));
+                    None
+                }
+                RBTreeMapOp::Insert(key, value) => {
+                    // Like Compute below, the callback can't fire yet: the
+                    // "old" value it reports is the one left by the same-key
+                    // write that precedes this one in the batch, or the
+                    // pre-batch value if none does, which is only known once
+                    // this transform actually runs during the merge.
+                    let callback = op.1;
+                    let transform: Slot<V> = Box::new(move |old| {
+                        callback(RBTreeMapRes::Value(old));
+                        Some(value)
+                    });
+                    Some(WrappedOp(RBTreeMapOp::Insert(key, (index, transform)), Box::new(|_| {})))
+                }
+                RBTreeMapOp::Remove(key) => {
+                    let callback = op.1;
+                    let transform: Slot<V> = Box::new(move |old| {
+                        callback(RBTreeMapRes::Value(old));
+                        None
+                    });
+                    Some(WrappedOp(RBTreeMapOp::Insert(key, (index, transform)), Box::new(|_| {})))
+                }
+                RBTreeMapOp::Compute(key, f) => {
+                    // Same reasoning as Insert/Remove above: the value fed
+                    // in depends on whatever earlier same-key write in this
+                    // batch (if any) it gets composed after.
+                    let callback = op.1;
+                    let transform: Slot<V> = Box::new(move |old| {
+                        let value = f(old);
+                        callback(RBTreeMapRes::Value(Some(value.clone())));
+                        Some(value)
+                    });
+                    Some(WrappedOp(RBTreeMapOp::Insert(key, (index, transform)), Box::new(|_| {})))
+                }
+            })
+            .collect();
+
+        if !mutations.is_empty() {
+            let mut merged = utils::parallel_reduce(mutations, reduce, map).await;
+            self.apply_merge(&mut merged.pool, merged.root);
+        }
+
+        for (query, callback) in reads {
+            let res = match query {
+                ReadQuery::Get(key) => RBTreeMapRes::Value(self.get(&key).cloned()),
+                ReadQuery::Range(start, end) => RBTreeMapRes::Entries(
+                    self.range((start, end)).map(|(k, v)| (k.clone(), v.clone())).collect(),
+                ),
+                ReadQuery::Above(key) => {
+                    RBTreeMapRes::Entry(self.above(&key).map(|(k, v)| (k.clone(), v.clone())))
+                }
+                ReadQuery::Below(key) => {
+                    RBTreeMapRes::Entry(self.below(&key).map(|(k, v)| (k.clone(), v.clone())))
+                }
+            };
+            callback(res);
+        }
+    }
+}
+
+impl<K: Ord, V: Clone> RBTreeMap<K, V> {
+    /// Folds a merged, tagged transform tree into `self`, feeding each
+    /// transform the key's pre-batch value so a lone `Compute` sees the
+    /// real current state, not just an earlier same-batch write.
+    fn apply_merge(&mut self, pool: &mut NodePool<K, (usize, Slot<V>)>, handle: Option<NodeHandle>) {
+        if let Some(handle) = handle {
+            let PoolNode { key, value: (_, transform), left, right, .. } = pool.take(handle);
+            self.apply_merge(pool, left);
+            let old = self.get(&key).cloned();
+            match transform(old) {
+                Some(value) => { self.insert(key, value); }
+                None => { self.remove(&key); }
+            }
+            self.apply_merge(pool, right);
+        }
     }
 }
 
+mod common;
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use common::SimpleRng;
+    use std::sync::{Arc, Mutex};
 
     #[test]
     fn test_insert_and_get() {
@@ -291,6 +1011,49 @@ mod tests {
         assert_eq!(map.get(&6), None);
     }
 
+    #[test]
+    fn test_iter_is_in_order() {
+        let mut map = RBTreeMap::new();
+        for key in [5, 3, 8, 1, 4, 7, 9] {
+            map.insert(key, key * 10);
+        }
+        let collected: Vec<_> = map.iter().map(|(k, v)| (*k, *v)).collect();
+        assert_eq!(collected, vec![(1, 10), (3, 30), (4, 40), (5, 50), (7, 70), (8, 80), (9, 90)]);
+    }
+
+    #[test]
+    fn test_range_bounds() {
+        let mut map = RBTreeMap::new();
+        for key in 0..10 {
+            map.insert(key, key * 10);
+        }
+
+        let inclusive: Vec<_> = map.range(3..=6).map(|(k, v)| (*k, *v)).collect();
+        assert_eq!(inclusive, vec![(3, 30), (4, 40), (5, 50), (6, 60)]);
+
+        let exclusive: Vec<_> = map.range(3..6).map(|(k, v)| (*k, *v)).collect();
+        assert_eq!(exclusive, vec![(3, 30), (4, 40), (5, 50)]);
+
+        let unbounded_below: Vec<_> = map.range(..3).map(|(k, v)| (*k, *v)).collect();
+        assert_eq!(unbounded_below, vec![(0, 0), (1, 10), (2, 20)]);
+    }
+
+    #[test]
+    fn test_above_and_below() {
+        let mut map = RBTreeMap::new();
+        for key in [2, 4, 6, 8] {
+            map.insert(key, key * 10);
+        }
+
+        assert_eq!(map.above(&4), Some((&6, &60)));
+        assert_eq!(map.above(&8), None);
+        assert_eq!(map.below(&6), Some((&4, &40)));
+        assert_eq!(map.below(&2), None);
+        // A key need not be present in the tree for above/below to work.
+        assert_eq!(map.above(&5), Some((&6, &60)));
+        assert_eq!(map.below(&5), Some((&4, &40)));
+    }
+
     #[test]
     fn test_insert_overwrite() {
         let mut map = RBTreeMap::new();
@@ -349,24 +1112,78 @@ mod tests {
         }
     }
 
-    struct SimpleRng {
-        seed: u64,
+    #[test]
+    fn test_reverse_comparator() {
+        struct Reverse;
+        impl Comparator<i32> for Reverse {
+            fn cmp(&self, a: &i32, b: &i32) -> Ordering {
+                b.cmp(a)
+            }
+        }
+
+        let mut map = RBTreeMapBy::with_comparator(Reverse);
+        for key in [5, 3, 8, 1, 4] {
+            map.insert(key, key * 10);
+        }
+        let collected: Vec<_> = map.iter().map(|(k, v)| (*k, *v)).collect();
+        assert_eq!(collected, vec![(8, 80), (5, 50), (4, 40), (3, 30), (1, 10)]);
+        assert_eq!(map.get(&3), Some(&30));
+
+        // `above`/`below` walk nearest-neighbor in `cmp`'s order, not `K`'s
+        // own `Ord`, so under `Reverse` the "neighbor above 5" is the next
+        // entry towards 1, and the "neighbor below 5" is the next one
+        // towards 8.
+        assert_eq!(map.above(&5), Some((&4, &40)));
+        assert_eq!(map.below(&5), Some((&8, &80)));
+
+        assert_eq!(map.remove(&3), Some(30));
+        assert_eq!(map.get(&3), None);
+
+        // `range` is only available on `OrdComparator` maps (see
+        // `RBTreeMapBy::range`'s doc comment): `RangeBounds<K>` is fixed in
+        // `K`'s own `Ord` order, so pruning it against a comparator that
+        // disagrees with that order -- such as `Reverse` here -- would
+        // silently flip which entries come out. `map.range(3..=6)` is a
+        // compile error, which is the point: `RBTreeMapBy<i32, i32, Reverse>`
+        // has no `range` method to call.
     }
 
-    impl SimpleRng {
-        const A: u64 = 6364136223846793005;
-        const C: u64 = 1;
+    #[test]
+    fn test_entry_or_insert_vacant_and_occupied() {
+        let mut map = RBTreeMap::new();
 
-        fn new(seed: u64) -> Self { SimpleRng { seed } }
+        *map.entry(1).or_insert(10) += 1;
+        assert_eq!(map.get(&1), Some(&11));
 
-        fn next(&mut self) -> u64 {
-            self.seed = self.seed.wrapping_mul(Self::A).wrapping_add(Self::C);
-            self.seed
-        }
+        *map.entry(1).or_insert(100) += 1;
+        assert_eq!(map.get(&1), Some(&12));
+    }
 
-        fn gen_range(&mut self, min: u64, max: u64) -> u64 {
-            min + (self.next() % (max - min))
-        }
+    #[test]
+    fn test_entry_or_insert_with_only_calls_default_on_miss() {
+        let mut map = RBTreeMap::new();
+        map.insert(1, 5);
+
+        let mut calls = 0;
+        map.entry(1).or_insert_with(|| { calls += 1; 999 });
+        assert_eq!(calls, 0);
+        assert_eq!(map.get(&1), Some(&5));
+
+        map.entry(2).or_insert_with(|| { calls += 1; 999 });
+        assert_eq!(calls, 1);
+        assert_eq!(map.get(&2), Some(&999));
+    }
+
+    #[test]
+    fn test_entry_and_modify() {
+        let mut map = RBTreeMap::new();
+        map.insert(1, 5);
+
+        map.entry(1).and_modify(|v| *v *= 2).or_insert(0);
+        assert_eq!(map.get(&1), Some(&10));
+
+        map.entry(2).and_modify(|v| *v *= 2).or_insert(7);
+        assert_eq!(map.get(&2), Some(&7));
     }
 
     #[test]
@@ -385,7 +1202,7 @@ mod tests {
             map.insert(key, value);
             keys.push((key, value));
         }
-        
+
         // Check if all inserted keys return the correct values
         for (key, value) in keys {
             assert_eq!(map.get(&key), Some(&value));
@@ -451,4 +1268,82 @@ mod tests {
             assert_eq!(map.get(&key), Some(&value));
         }
     }
+
+    fn run(map: &mut RBTreeMap<u64, u64>, ops: Vec<RBTreeMapOp<u64, u64>>) -> Vec<RBTreeMapRes<u64, u64>> {
+        let results = Arc::new(Mutex::new(Vec::new()));
+        let wrapped = ops.into_iter().map(|op| {
+            let results = results.clone();
+            WrappedOp(op, Box::new(move |res| results.lock().unwrap().push(res)) as Box<dyn FnOnce(RBTreeMapRes<u64, u64>) + Send>)
+        }).collect();
+        futures::executor::block_on(map.run_batch(wrapped));
+        Arc::try_unwrap(results).unwrap().into_inner().unwrap()
+    }
+
+    #[test]
+    fn test_run_batch_matches_sequential_reference() {
+        let mut reference = RBTreeMap::new();
+        let mut batched = RBTreeMap::new();
+        let mut rng = SimpleRng::new(2024);
+        let mut ops = Vec::new();
+        for _ in 0 .. 2000 {
+            let key = rng.gen_range(0, 500);
+            let value = rng.gen_range(0, 1_000_000);
+            if rng.gen_range(0, 5) == 0 {
+                reference.remove(&key);
+                ops.push(RBTreeMapOp::Remove(key));
+            } else {
+                reference.insert(key, value);
+                ops.push(RBTreeMapOp::Insert(key, value));
+            }
+        }
+
+        run(&mut batched, ops);
+
+        for key in 0 .. 500 {
+            assert_eq!(batched.get(&key), reference.get(&key));
+        }
+    }
+
+    #[test]
+    fn test_run_batch_insert_sees_in_batch_predecessor() {
+        let mut map = RBTreeMap::new();
+        map.insert(7, 1);
+
+        let results = run(&mut map, vec![
+            RBTreeMapOp::Insert(7, 111),
+            RBTreeMapOp::Insert(7, 222),
+            RBTreeMapOp::Get(7),
+        ]);
+
+        assert!(matches!(results[0], RBTreeMapRes::Value(Some(1))));
+        assert!(matches!(results[1], RBTreeMapRes::Value(Some(111))));
+        assert!(matches!(results[2], RBTreeMapRes::Value(Some(222))));
+        assert_eq!(map.get(&7), Some(&222));
+    }
+
+    #[test]
+    fn test_run_batch_compute_chains_in_batch_order() {
+        let mut map = RBTreeMap::new();
+        map.insert(1, 10);
+
+        let results = Arc::new(Mutex::new(Vec::new()));
+        let r1 = results.clone();
+        let r2 = results.clone();
+        let wrapped = vec![
+            WrappedOp(
+                RBTreeMapOp::Compute(1, Box::new(|old: Option<u64>| old.unwrap() + 1)),
+                Box::new(move |res: RBTreeMapRes<u64, u64>| r1.lock().unwrap().push(res)) as Box<dyn FnOnce(RBTreeMapRes<u64, u64>) + Send>,
+            ),
+            WrappedOp(
+                RBTreeMapOp::Compute(1, Box::new(|old: Option<u64>| old.unwrap() * 2)),
+                Box::new(move |res: RBTreeMapRes<u64, u64>| r2.lock().unwrap().push(res)) as Box<dyn FnOnce(RBTreeMapRes<u64, u64>) + Send>,
+            ),
+        ];
+        futures::executor::block_on(map.run_batch(wrapped));
+
+        assert_eq!(map.get(&1), Some(&22));
+        let results = results.lock().unwrap();
+        assert!(matches!(results[0], RBTreeMapRes::Value(Some(11))));
+        assert!(matches!(results[1], RBTreeMapRes::Value(Some(22))));
+    }
 }